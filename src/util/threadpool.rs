@@ -15,13 +15,21 @@ use std::usize;
 use std::sync::{Arc, Mutex, Condvar};
 use std::thread::{Builder, JoinHandle};
 use std::boxed::FnBox;
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::cmp::Ordering;
-use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering as AtomicOrdering};
 use std::hash::Hash;
 use std::marker::PhantomData;
 use std::fmt::{self, Write, Debug, Formatter};
-use std::sync::mpsc::{Sender, Receiver, channel};
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+extern crate crossbeam_channel;
+use crossbeam_channel::{Sender, Receiver, RecvTimeoutError, unbounded};
+
+extern crate futures;
+use futures::{Future, Poll};
+use futures::sync::oneshot::{self, Canceled};
 
 const DEFAULT_QUEUE_CAPACITY: usize = 1000;
 const QUEUE_MAX_CAPACITY: usize = 8 * DEFAULT_QUEUE_CAPACITY;
@@ -114,12 +122,95 @@ impl<T: Hash + Ord + Send + Clone + Debug, C> ScheduleQueue<T, C> for FifoQueue<
     fn on_task_finished(&mut self, _: &T) {}
 }
 
+/// A `ScheduleQueue` that gives every group a fair share of worker time:
+/// `pop` prefers whichever ready group has the fewest running tasks, with
+/// an optional per-group cap on concurrently running tasks.
+pub struct FairQueue<T, C> {
+    pending: HashMap<T, VecDeque<Task<T, C>>>,
+    running: HashMap<T, usize>,
+    max_running: Option<usize>,
+}
+
+impl<T: Hash + Ord + Send + Clone + Debug, C: Context> FairQueue<T, C> {
+    pub fn new() -> FairQueue<T, C> {
+        FairQueue {
+            pending: HashMap::new(),
+            running: HashMap::new(),
+            max_running: None,
+        }
+    }
+
+    pub fn with_max_running_per_group(max_running: usize) -> FairQueue<T, C> {
+        FairQueue { max_running: Some(max_running), ..FairQueue::new() }
+    }
+
+    fn running_count(&self, gid: &T) -> usize {
+        self.running.get(gid).cloned().unwrap_or(0)
+    }
+
+    // Picks the gid to serve next. When `respect_cap` is set, groups
+    // already at `max_running` are skipped entirely; the caller falls
+    // back to an unrestricted search if that leaves nothing eligible.
+    fn select_group(&self, respect_cap: bool) -> Option<T> {
+        let mut best: Option<(usize, u64, &T)> = None;
+        for (gid, deque) in &self.pending {
+            let front_id = match deque.front() {
+                Some(task) => task.id,
+                None => continue,
+            };
+            let running = self.running_count(gid);
+            if respect_cap {
+                if let Some(cap) = self.max_running {
+                    if running >= cap {
+                        continue;
+                    }
+                }
+            }
+            let candidate = (running, front_id, gid);
+            if best.as_ref().map_or(true, |b| candidate < *b) {
+                best = Some(candidate);
+            }
+        }
+        best.map(|(_, _, gid)| gid.clone())
+    }
+}
+
+impl<T: Hash + Ord + Send + Clone + Debug, C> ScheduleQueue<T, C> for FairQueue<T, C> {
+    fn push(&mut self, task: Task<T, C>) {
+        self.pending.entry(task.gid.clone()).or_insert_with(VecDeque::new).push_back(task);
+    }
+
+    fn pop(&mut self) -> Option<Task<T, C>> {
+        let gid = self.select_group(true).or_else(|| self.select_group(false))?;
+        let (task, now_empty) = {
+            let deque = self.pending.get_mut(&gid).unwrap();
+            (deque.pop_front().unwrap(), deque.is_empty())
+        };
+        if now_empty {
+            self.pending.remove(&gid);
+        }
+        Some(task)
+    }
+
+    fn on_task_started(&mut self, gid: &T) {
+        *self.running.entry(gid.clone()).or_insert(0) += 1;
+    }
+
+    fn on_task_finished(&mut self, gid: &T) {
+        if let Some(count) = self.running.get_mut(gid) {
+            *count = count.saturating_sub(1);
+        }
+        if self.running.get(gid) == Some(&0) {
+            self.running.remove(gid);
+        }
+    }
+}
+
 struct TaskPool<Q, T, C> {
     next_task_id: u64,
     task_queue: Q,
     marker: PhantomData<T>,
     stop: bool,
-    jobs: Receiver<Task<T, C>>,
 }
 
 impl<Q, T, C> TaskPool<Q, T, C>
@@ -127,13 +218,12 @@ impl<Q, T, C> TaskPool<Q, T, C>
           T: Debug,
           C: Context
 {
-    fn new(queue: Q, jobs: Receiver<Task<T, C>>) -> TaskPool<Q, T, C> {
+    fn new(queue: Q) -> TaskPool<Q, T, C> {
         TaskPool {
             next_task_id: 0,
             task_queue: queue,
             marker: PhantomData,
             stop: false,
-            jobs: jobs,
         }
     }
 
@@ -146,20 +236,17 @@ impl<Q, T, C> TaskPool<Q, T, C>
     }
 
     fn pop_task(&mut self) -> Option<Task<T, C>> {
-        if let Some(task) = self.task_queue.pop() {
-            return Some(task);
-        }
-        // try fill queue when queue is empty.
-        self.try_fill_queue();
         self.task_queue.pop()
     }
 
-    fn try_fill_queue(&mut self) {
-        while let Ok(mut task) = self.jobs.try_recv() {
-            task.id = self.next_task_id;
-            self.next_task_id += 1;
-            self.task_queue.push(task);
-        }
+    // Assigns the task its id and hands it to the schedule queue. Called
+    // whenever a worker pulls a task off the channel, so the scheduling
+    // decision (group throttling, FIFO order) still happens here rather
+    // than being first-come-first-served straight off the channel.
+    fn enqueue(&mut self, mut task: Task<T, C>) {
+        task.id = self.next_task_id;
+        self.next_task_id += 1;
+        self.task_queue.push(task);
     }
 
     #[inline]
@@ -184,15 +271,48 @@ pub trait ContextFactory<Ctx: Context> {
     fn create_context(&self) -> Ctx;
 }
 
+/// A handle to a task submitted via `ThreadPool::execute_handle`.
+pub struct TaskHandle<R> {
+    // `None` only after `wait()` has consumed it; `poll()` never leaves it
+    // empty since it returns as soon as the inner receiver resolves.
+    receiver: Option<oneshot::Receiver<R>>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl<R> TaskHandle<R> {
+    /// Blocks the calling thread until the task finishes and returns its
+    /// result, or `Canceled` if the task was dropped without running.
+    pub fn wait(mut self) -> Result<R, Canceled> {
+        self.receiver.take().unwrap().wait()
+    }
+}
+
+impl<R> Future for TaskHandle<R> {
+    type Item = R;
+    type Error = Canceled;
+
+    fn poll(&mut self) -> Poll<R, Canceled> {
+        self.receiver.as_mut().unwrap().poll()
+    }
+}
+
+impl<R> Drop for TaskHandle<R> {
+    fn drop(&mut self) {
+        self.cancelled.store(true, AtomicOrdering::SeqCst);
+    }
+}
+
 /// `ThreadPool` is used to execute tasks in parallel.
 /// Each task would be pushed into the pool, and when a thread
 /// is ready to process a task, it will get a task from the pool
 /// according to the `ScheduleQueue` provided in initialization.
 pub struct ThreadPool<Q, T, C, Ctx> {
-    task_pool: Arc<(Mutex<TaskPool<Q, T, Ctx>>, Condvar)>,
+    task_pool: Arc<Mutex<TaskPool<Q, T, Ctx>>>,
     threads: Vec<JoinHandle<()>>,
     task_count: Arc<AtomicUsize>,
-    sender: Sender<Task<T, Ctx>>,
+    // `None` once `stop()` has run; dropping the sender closes the channel,
+    // which immediately unblocks every worker parked in `jobs.recv()`.
+    sender: Option<Sender<Task<T, Ctx>>>,
     // ctx_factory should only be used in one thread
     ctx_factory: C,
 }
@@ -204,19 +324,33 @@ impl<Q, T, C, Ctx> ThreadPool<Q, T, C, Ctx>
           C: ContextFactory<Ctx>
 {
     pub fn new(name: String, num_threads: usize, queue: Q, f: C) -> ThreadPool<Q, T, C, Ctx> {
+        Self::with_throttling(name, num_threads, queue, f, None)
+    }
+
+    /// Like `new`, but each worker parks for up to `throttling` and runs
+    /// every task that became ready in that window as one batch, instead
+    /// of waking once per task. Pass `None` to keep the default
+    /// wake-per-task behavior.
+    pub fn with_throttling(name: String,
+                            num_threads: usize,
+                            queue: Q,
+                            f: C,
+                            throttling: Option<Duration>)
+                            -> ThreadPool<Q, T, C, Ctx> {
         assert!(num_threads >= 1);
-        let (sender, receiver) = channel::<Task<T, Ctx>>();
-        let task_pool = Arc::new((Mutex::new(TaskPool::new(queue, receiver)), Condvar::new()));
+        let (sender, receiver) = unbounded::<Task<T, Ctx>>();
+        let task_pool = Arc::new(Mutex::new(TaskPool::new(queue)));
         let mut threads = Vec::with_capacity(num_threads);
         let task_count = Arc::new(AtomicUsize::new(0));
         // Threadpool threads
         for _ in 0..num_threads {
             let tasks = task_pool.clone();
             let task_num = task_count.clone();
+            let jobs = receiver.clone();
             let thread = Builder::new()
                 .name(name.clone())
                 .spawn(move || {
-                    let mut worker = Worker::new(tasks, task_num);
+                    let mut worker = Worker::new(tasks, jobs, task_num, throttling);
                     worker.run();
                 })
                 .unwrap();
@@ -227,7 +361,7 @@ impl<Q, T, C, Ctx> ThreadPool<Q, T, C, Ctx>
             task_pool: task_pool,
             threads: threads,
             task_count: task_count,
-            sender: sender,
+            sender: Some(sender),
             ctx_factory: f,
         }
     }
@@ -238,10 +372,79 @@ impl<Q, T, C, Ctx> ThreadPool<Q, T, C, Ctx>
     {
         let ctx = self.ctx_factory.create_context();
         let task = Task::new(gid, job, ctx);
-        self.sender.send(task).unwrap();
+        // Multiple callers sending concurrently only contend on
+        // crossbeam's lock-free channel, not on the task-pool mutex that
+        // guards scheduling.
+        self.sender.as_ref().unwrap().send(task).unwrap();
         self.task_count.fetch_add(1, AtomicOrdering::SeqCst);
-        let &(_, ref cvar) = &*self.task_pool;
-        cvar.notify_one();
+    }
+
+    /// Like `execute`, but returns a `TaskHandle` the caller can use to
+    /// retrieve `job`'s return value instead of it being fire-and-forget.
+    /// Dropping the handle before the task runs sets its cancelled flag,
+    /// which the task checks right before calling `job`, so an abandoned
+    /// handle skips the work instead of running it for nothing.
+    pub fn execute_handle<F, R>(&mut self, gid: T, job: F) -> TaskHandle<R>
+        where F: FnOnce(Ctx) -> R + Send + 'static,
+              R: Send + 'static,
+              Ctx: Context
+    {
+        let (tx, rx) = oneshot::channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let task_cancelled = cancelled.clone();
+        self.execute(gid, move |ctx: Ctx| {
+            if task_cancelled.load(AtomicOrdering::SeqCst) {
+                return;
+            }
+            // If the handle (and its receiver) was already dropped, nobody
+            // is waiting for the result any more; `send` failing is fine.
+            let _ = tx.send(job(ctx));
+        });
+        TaskHandle {
+            receiver: Some(rx),
+            cancelled: cancelled,
+        }
+    }
+
+    /// Runs `f` exactly once on each of the pool's worker threads, blocking
+    /// until every one of them has executed it.
+    ///
+    /// All `num_threads` sentinels share one group (`T::default()`), and
+    /// each stays "running" in the schedule queue's eyes for the entire
+    /// broadcast, not just its own `f`. With a `FairQueue` capped below
+    /// `num_threads` via `with_max_running_per_group`, and other groups
+    /// with a steady supply of pending work, the fair-scheduling cap can
+    /// keep this group from ever getting picked again once it hits the
+    /// cap, hanging `broadcast` indefinitely. Don't pair `broadcast` with
+    /// a per-group cap lower than `num_threads`.
+    pub fn broadcast<F>(&mut self, f: F)
+        where F: Fn(Ctx) + Send + Sync + 'static,
+              T: Default
+    {
+        let num_threads = self.threads.len();
+        let latch = Arc::new((Mutex::new(num_threads), Condvar::new()));
+        let f = Arc::new(f);
+        for _ in 0..num_threads {
+            let latch = latch.clone();
+            let f = f.clone();
+            self.execute(T::default(), move |ctx: Ctx| {
+                f(ctx);
+                let &(ref lock, ref cvar) = &*latch;
+                let mut remaining = lock.lock().unwrap();
+                *remaining -= 1;
+                if *remaining == 0 {
+                    cvar.notify_all();
+                }
+                while *remaining != 0 {
+                    remaining = cvar.wait(remaining).unwrap();
+                }
+            });
+        }
+        let &(ref lock, ref cvar) = &*latch;
+        let mut remaining = lock.lock().unwrap();
+        while *remaining != 0 {
+            remaining = cvar.wait(remaining).unwrap();
+        }
     }
 
     #[inline]
@@ -251,11 +454,12 @@ impl<Q, T, C, Ctx> ThreadPool<Q, T, C, Ctx>
 
     pub fn stop(&mut self) -> Result<(), String> {
         {
-            let &(ref lock, ref cvar) = &*self.task_pool;
-            let mut tasks = lock.lock().unwrap();
+            let mut tasks = self.task_pool.lock().unwrap();
             tasks.stop();
-            cvar.notify_all();
         }
+        // Dropping the sender disconnects the channel, which wakes every
+        // worker blocked in `jobs.recv()` right away.
+        self.sender = None;
         let mut err_msg = String::new();
         for t in self.threads.drain(..) {
             if let Err(e) = t.join() {
@@ -269,10 +473,22 @@ impl<Q, T, C, Ctx> ThreadPool<Q, T, C, Ctx>
     }
 }
 
+// Outcome of a single non-blocking attempt to take a task off the queue.
+enum PollResult<T, C> {
+    Task(Task<T, C>),
+    // Nothing ready right now, but the pool is still running.
+    Empty,
+    Stopped,
+}
+
 // Each thread has a worker.
 struct Worker<Q, T, C> {
-    task_pool: Arc<(Mutex<TaskPool<Q, T, C>>, Condvar)>,
+    task_pool: Arc<Mutex<TaskPool<Q, T, C>>>,
+    jobs: Receiver<Task<T, C>>,
     task_count: Arc<AtomicUsize>,
+    // When set, the worker batches ready tasks on a fixed tick instead of
+    // waking for every single one; see `run_throttled`.
+    throttling: Option<Duration>,
 }
 
 impl<Q, T, C> Worker<Q, T, C>
@@ -280,41 +496,129 @@ impl<Q, T, C> Worker<Q, T, C>
           T: Debug,
           C: Context
 {
-    fn new(task_pool: Arc<(Mutex<TaskPool<Q, T, C>>, Condvar)>,
-           task_count: Arc<AtomicUsize>)
+    fn new(task_pool: Arc<Mutex<TaskPool<Q, T, C>>>,
+           jobs: Receiver<Task<T, C>>,
+           task_count: Arc<AtomicUsize>,
+           throttling: Option<Duration>)
            -> Worker<Q, T, C> {
         Worker {
             task_pool: task_pool,
+            jobs: jobs,
             task_count: task_count,
+            throttling: throttling,
         }
     }
 
-    // `get_next_task` return `None` when `task_pool` is stopped.
-    #[inline]
-    fn get_next_task(&self, prev_gid: Option<&T>) -> Option<Task<T, C>> {
-        // try to receive notification.
-        let &(ref lock, ref cvar) = &*self.task_pool;
-        let mut task_pool = lock.lock().unwrap();
-        if prev_gid.is_some() {
-            task_pool.on_task_finished(prev_gid.unwrap());
+    // Takes one task off the queue without blocking, refilling the queue
+    // from the channel first. `prev_gid`, if any, belongs to the task this
+    // worker just finished running, so the schedule queue can account for
+    // it before deciding what to hand out next.
+    fn try_pop_task(&self, prev_gid: Option<&T>) -> PollResult<T, C> {
+        let mut task_pool = self.task_pool.lock().unwrap();
+        if let Some(gid) = prev_gid {
+            task_pool.on_task_finished(gid);
         }
-        loop {
-            if task_pool.is_stopped() {
-                return None;
-            }
-            if let Some(task) = task_pool.pop_task() {
+        if task_pool.is_stopped() {
+            return PollResult::Stopped;
+        }
+        // Drain anything already buffered on the channel before falling
+        // back to a blocking receive, so the scheduling decision still
+        // happens in `pop_task` instead of being first-come-first-served
+        // straight off the channel.
+        while let Ok(task) = self.jobs.try_recv() {
+            task_pool.enqueue(task);
+        }
+        match task_pool.pop_task() {
+            Some(task) => {
                 // `on_task_started` should be here since:
                 //  1. To reduce lock's time;
                 //  2. For some schedula_queue,on_task_started should be
                 //  in the same lock with `pop_task` for the thread safety.
                 task_pool.on_task_started(&task.gid);
-                return Some(task);
+                PollResult::Task(task)
+            }
+            None => PollResult::Empty,
+        }
+    }
+
+    // `get_next_task` return `None` when `task_pool` is stopped.
+    #[inline]
+    fn get_next_task(&self, mut prev_gid: Option<&T>) -> Option<Task<T, C>> {
+        loop {
+            match self.try_pop_task(prev_gid) {
+                PollResult::Task(task) => return Some(task),
+                PollResult::Stopped => return None,
+                PollResult::Empty => {}
+            }
+            // `on_task_finished` only needs to run once per finished task.
+            prev_gid = None;
+            // The shared queue is empty; block on the channel itself
+            // instead of a `Condvar`. This is crossbeam's wakeup +
+            // transport primitive: any `execute` call wakes exactly the
+            // worker(s) crossbeam picks, with no extra lock in the way,
+            // so there's no `notify_all` thundering herd left to replace
+            // here (see `AdaptiveSleep`, which solves that problem where
+            // it still exists, in `WorkStealingPool`).
+            match self.jobs.recv() {
+                Ok(task) => {
+                    let mut task_pool = self.task_pool.lock().unwrap();
+                    task_pool.enqueue(task);
+                }
+                Err(_) => return None, // sender dropped: the pool is stopping.
+            }
+        }
+    }
+
+    // Parks until the next `throttling` tick, then drains and runs every
+    // currently ready task in one batch before parking again, instead of
+    // waking once per task. `recv_timeout` against the remaining time to
+    // the next tick doubles as the wakeup primitive: after a batch that ran
+    // nothing, a task pushed while we're parked wakes us immediately (so
+    // the first task after idle never waits a full tick); after a non-empty
+    // batch, tasks that arrive are only queued until the tick. `stop()`
+    // dropping the sender unblocks the wait immediately either way.
+    fn run_throttled(&mut self, throttling: Duration) {
+        let mut prev_gid: Option<T> = None;
+        let mut last_tick = Instant::now();
+        loop {
+            let mut ran_any = false;
+            loop {
+                match self.try_pop_task(prev_gid.as_ref()) {
+                    PollResult::Task(task) => {
+                        task.ctx.on_start();
+                        (task.task)(task.ctx.clone());
+                        task.ctx.on_complete();
+                        self.task_count.fetch_sub(1, AtomicOrdering::SeqCst);
+                        prev_gid = Some(task.gid);
+                        ran_any = true;
+                    }
+                    PollResult::Empty => break,
+                    PollResult::Stopped => return,
+                }
+            }
+            last_tick += throttling;
+            loop {
+                let remaining = last_tick.saturating_duration_since(Instant::now());
+                match self.jobs.recv_timeout(remaining) {
+                    Ok(task) => {
+                        let mut task_pool = self.task_pool.lock().unwrap();
+                        task_pool.enqueue(task);
+                        if !ran_any {
+                            break;
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
             }
-            task_pool = cvar.wait(task_pool).unwrap();
         }
     }
 
     fn run(&mut self) {
+        if let Some(throttling) = self.throttling {
+            self.run_throttled(throttling);
+            return;
+        }
         let mut task = self.get_next_task(None);
         // Start the worker. Loop breaks when receive stop message.
         while let Some(t) = task {
@@ -329,12 +633,348 @@ impl<Q, T, C> Worker<Q, T, C>
     }
 }
 
+// Tracks which `WorkStealingPool` worker (if any) the current OS thread is
+// running as, so `WorkStealingPool::execute` can push onto that worker's own
+// deque instead of the shared injector when it's called from within a task.
+//
+// Keyed by `pool_id` (not just the worker index) because two distinct pools
+// can otherwise hand out the same worker index, and a raw pointer captured
+// on one pool's thread must never be reinterpreted as belonging to another.
+// `pool_id` is assigned once per pool from `POOL_ID_GEN` and never reused,
+// so matching it on the read side guarantees the pointer's pointee type and
+// lifetime are exactly what the write side promised.
+thread_local! {
+    static CURRENT_WORKER: Cell<Option<(usize, usize, *const ())>> = Cell::new(None);
+}
+
+static POOL_ID_GEN: AtomicUsize = AtomicUsize::new(0);
+
+// A tiny xorshift PRNG so victim selection for stealing doesn't need an
+// external RNG crate.
+struct XorShiftRng {
+    state: u64,
+}
+
+impl XorShiftRng {
+    fn new(seed: u64) -> XorShiftRng {
+        XorShiftRng { state: if seed == 0 { 0x9e3779b97f4a7c15 } else { seed } }
+    }
+
+    fn next(&mut self, bound: usize) -> usize {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state = x;
+        (x as usize) % bound
+    }
+}
+
+// `WorkStealingPool`'s per-worker deques are the real Chase-Lev lock-free
+// deque from `crossbeam_deque` (its overflow queue is that crate's
+// lock-free FIFO `Injector`), rather than a
+// `Mutex<VecDeque<_>>` stand-in: stealing relies on its atomic bottom/top
+// indices to guarantee a pop and a steal racing for the last task resolve
+// to exactly one winner, which a mutex around a `VecDeque` can't promise
+// under the same invariant rayon-core relies on.
+extern crate crossbeam_deque;
+use crossbeam_deque::{Injector as StealInjector, Steal, Stealer, Worker as StealDeque};
+
+// Bits 32..64 of `AdaptiveSleep::state` hold a monotonic "jobs happened"
+// counter; bits 0..32 hold the number of workers currently parked on the
+// condvar (as opposed to merely "sleepy").
+const SLEEPER_BITS: u32 = 32;
+const SLEEPER_MASK: u64 = (1 << SLEEPER_BITS) - 1;
+const ONE_JOB: u64 = 1 << SLEEPER_BITS;
+const SLEEP_SPIN_ROUNDS: u32 = 64;
+
+fn unpack_sleep_state(state: u64) -> (u32, u32) {
+    ((state >> SLEEPER_BITS) as u32, (state & SLEEPER_MASK) as u32)
+}
+
+// Targets `WorkStealingPool`/`StealingWorker`, not `ThreadPool`'s `Worker`:
+// by the time this was written, `Worker::get_next_task` already blocked on
+// a crossbeam-channel `recv()` rather than a `Condvar` (see chunk0-2), so
+// `stop()`'s `notify_all`-style thundering herd it was meant to replace no
+// longer exists there — a channel with multiple receivers already wakes
+// exactly one per send. `WorkStealingPool`'s idle-spin-then-park loop is
+// where that problem is still real, so the state machine lives here.
+/// Two-counter sleep state machine, used in place of a bare
+/// `notify_one`/`notify_all` so idle workers neither miss a wakeup nor all
+/// get woken for one task.
+struct AdaptiveSleep {
+    state: AtomicU64,
+    parked: Mutex<()>,
+    cvar: Condvar,
+}
+
+impl AdaptiveSleep {
+    fn new() -> AdaptiveSleep {
+        AdaptiveSleep {
+            state: AtomicU64::new(0),
+            parked: Mutex::new(()),
+            cvar: Condvar::new(),
+        }
+    }
+
+    fn jobs_counter(&self) -> u32 {
+        unpack_sleep_state(self.state.load(AtomicOrdering::SeqCst)).0
+    }
+
+    fn on_job_pushed(&self) {
+        let prev = self.state.fetch_add(ONE_JOB, AtomicOrdering::SeqCst);
+        let (_, sleepers) = unpack_sleep_state(prev);
+        if sleepers > 0 {
+            // Take the lock so we can't race a worker that's in the
+            // middle of re-checking the counter and parking.
+            let _guard = self.parked.lock().unwrap();
+            self.cvar.notify_one();
+        }
+    }
+
+    fn wake_all(&self) {
+        let _guard = self.parked.lock().unwrap();
+        self.cvar.notify_all();
+    }
+
+    // Parks the caller unless the jobs counter has advanced past
+    // `snapshot` since it was taken, in which case there's new work to go
+    // look for instead of sleeping. `timeout` is a safety net in case a
+    // wakeup is ever missed despite the above.
+    fn sleep(&self, snapshot: u32, timeout: Duration) {
+        let guard = self.parked.lock().unwrap();
+        // Count ourselves as a sleeper before re-checking the counter, so a
+        // push that lands after the check sees us and takes the lock.
+        self.state.fetch_add(1, AtomicOrdering::SeqCst);
+        if self.jobs_counter() != snapshot {
+            self.state.fetch_sub(1, AtomicOrdering::SeqCst);
+            return;
+        }
+        let _ = self.cvar.wait_timeout(guard, timeout).unwrap();
+        self.state.fetch_sub(1, AtomicOrdering::SeqCst);
+    }
+}
+
+struct StealingWorker<T, C> {
+    id: usize,
+    pool_id: usize,
+    local: StealDeque<Task<T, C>>,
+    stealers: Vec<Stealer<Task<T, C>>>,
+    injector: Arc<StealInjector<Task<T, C>>>,
+    stopped: Arc<AtomicBool>,
+    sleep: Arc<AdaptiveSleep>,
+    task_count: Arc<AtomicUsize>,
+    rng: XorShiftRng,
+}
+
+impl<T, C> StealingWorker<T, C>
+    where T: Debug,
+          C: Context
+{
+    // Pop from our own deque first, then the injector, then try to steal
+    // from the tail of a randomly chosen victim. `Steal::Retry` means
+    // another thief raced us for the same slot, so we just try again.
+    fn find_task(&mut self) -> Option<Task<T, C>> {
+        if let Some(task) = self.local.pop() {
+            return Some(task);
+        }
+        loop {
+            match self.injector.steal() {
+                Steal::Success(task) => return Some(task),
+                Steal::Empty => break,
+                Steal::Retry => continue,
+            }
+        }
+        let workers = self.stealers.len();
+        if workers > 1 {
+            let start = self.rng.next(workers);
+            for i in 0..workers {
+                let victim = (start + i) % workers;
+                if victim == self.id {
+                    continue;
+                }
+                loop {
+                    match self.stealers[victim].steal() {
+                        Steal::Success(task) => return Some(task),
+                        Steal::Empty => break,
+                        Steal::Retry => continue,
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn run_task(&mut self, task: Task<T, C>) {
+        task.ctx.on_start();
+        (task.task)(task.ctx.clone());
+        task.ctx.on_complete();
+        self.task_count.fetch_sub(1, AtomicOrdering::SeqCst);
+    }
+
+    fn run(&mut self) {
+        // Sound because `self.local` isn't moved again for the rest of
+        // `run()`'s lifetime, which is as long as this thread lives, and
+        // only this same thread ever reads this thread-local slot back.
+        let local_ptr = &self.local as *const StealDeque<Task<T, C>> as *const ();
+        CURRENT_WORKER.with(|c| c.set(Some((self.pool_id, self.id, local_ptr))));
+        let mut spins = 0u32;
+        loop {
+            if let Some(task) = self.find_task() {
+                self.run_task(task);
+                spins = 0;
+                continue;
+            }
+            if self.stopped.load(AtomicOrdering::SeqCst) {
+                return;
+            }
+            spins += 1;
+            if spins < SLEEP_SPIN_ROUNDS {
+                // Busy-spin a bounded number of rounds first; stealing is
+                // cheap and most idle periods are short.
+                continue;
+            }
+            // Still nothing after spinning: announce ourselves sleepy by
+            // recording the current jobs counter, then take one more look
+            // before actually parking.
+            let snapshot = self.sleep.jobs_counter();
+            if let Some(task) = self.find_task() {
+                self.run_task(task);
+                spins = 0;
+                continue;
+            }
+            if self.stopped.load(AtomicOrdering::SeqCst) {
+                return;
+            }
+            self.sleep.sleep(snapshot, Duration::from_millis(5));
+            spins = 0;
+        }
+    }
+}
+
+/// A thread pool that schedules tasks with a work-stealing strategy instead
+/// of routing every task through one shared `Mutex<TaskPool>`.
+pub struct WorkStealingPool<T, C, Ctx> {
+    pool_id: usize,
+    injector: Arc<StealInjector<Task<T, Ctx>>>,
+    threads: Vec<JoinHandle<()>>,
+    next_task_id: AtomicUsize,
+    task_count: Arc<AtomicUsize>,
+    stopped: Arc<AtomicBool>,
+    sleep: Arc<AdaptiveSleep>,
+    ctx_factory: C,
+    marker: PhantomData<Ctx>,
+}
+
+impl<T, C, Ctx> WorkStealingPool<T, C, Ctx>
+    where T: Hash + Send + Clone + 'static + Debug,
+          Ctx: Context + 'static,
+          C: ContextFactory<Ctx>
+{
+    pub fn new(name: String, num_threads: usize, f: C) -> WorkStealingPool<T, C, Ctx> {
+        assert!(num_threads >= 1);
+        let pool_id = POOL_ID_GEN.fetch_add(1, AtomicOrdering::SeqCst);
+        let locals: Vec<_> = (0..num_threads).map(|_| StealDeque::new_lifo()).collect();
+        let stealers: Vec<_> = locals.iter().map(|w| w.stealer()).collect();
+        let injector = Arc::new(StealInjector::new());
+        let stopped = Arc::new(AtomicBool::new(false));
+        let sleep = Arc::new(AdaptiveSleep::new());
+        let task_count = Arc::new(AtomicUsize::new(0));
+        let mut threads = Vec::with_capacity(num_threads);
+        for (id, local) in locals.into_iter().enumerate() {
+            let stealers = stealers.clone();
+            let injector = injector.clone();
+            let stopped = stopped.clone();
+            let sleep = sleep.clone();
+            let task_count = task_count.clone();
+            let thread = Builder::new()
+                .name(name.clone())
+                .spawn(move || {
+                    let mut worker = StealingWorker {
+                        id: id,
+                        pool_id: pool_id,
+                        local: local,
+                        stealers: stealers,
+                        injector: injector,
+                        stopped: stopped,
+                        sleep: sleep,
+                        task_count: task_count,
+                        rng: XorShiftRng::new((id as u64 + 1).wrapping_mul(0x9e3779b97f4a7c15)),
+                    };
+                    worker.run();
+                })
+                .unwrap();
+            threads.push(thread);
+        }
+
+        WorkStealingPool {
+            pool_id: pool_id,
+            injector: injector,
+            threads: threads,
+            next_task_id: AtomicUsize::new(0),
+            task_count: task_count,
+            stopped: stopped,
+            sleep: sleep,
+            ctx_factory: f,
+            marker: PhantomData,
+        }
+    }
+
+    pub fn execute<F>(&self, gid: T, job: F)
+        where F: FnOnce(Ctx) + Send + 'static
+    {
+        let ctx = self.ctx_factory.create_context();
+        let mut task = Task::new(gid, job, ctx);
+        task.id = self.next_task_id.fetch_add(1, AtomicOrdering::SeqCst) as u64;
+        self.task_count.fetch_add(1, AtomicOrdering::SeqCst);
+
+        let local_worker = CURRENT_WORKER.with(|c| c.get());
+        match local_worker {
+            Some((pool_id, _, ptr)) if pool_id == self.pool_id => {
+                // Safe: only this pool's own worker threads ever write a
+                // pointer under this `pool_id`, and only while that
+                // pointer's `StealDeque` is still alive on their stack.
+                let local_deque = unsafe { &*(ptr as *const StealDeque<Task<T, Ctx>>) };
+                local_deque.push(task);
+            }
+            _ => self.injector.push(task),
+        }
+
+        // Bumping the jobs counter before deciding whether to wake anyone
+        // is what makes this safe against a concurrently-sleeping worker
+        // missing the new task; see `AdaptiveSleep`.
+        self.sleep.on_job_pushed();
+    }
+
+    #[inline]
+    pub fn get_task_count(&self) -> usize {
+        self.task_count.load(AtomicOrdering::SeqCst)
+    }
+
+    pub fn stop(&mut self) -> Result<(), String> {
+        self.stopped.store(true, AtomicOrdering::SeqCst);
+        self.sleep.wake_all();
+        let mut err_msg = String::new();
+        for t in self.threads.drain(..) {
+            if let Err(e) = t.join() {
+                write!(&mut err_msg, "Failed to join thread with err: {:?};", e).unwrap();
+            }
+        }
+        if !err_msg.is_empty() {
+            return Err(err_msg);
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::{ThreadPool, Task, ScheduleQueue, FifoQueue, Context, ContextFactory};
+    use super::{ThreadPool, Task, ScheduleQueue, FifoQueue, FairQueue, Context, ContextFactory,
+                WorkStealingPool};
     use std::time::Duration;
     use std::sync::mpsc::channel;
     use std::sync::{Arc, Mutex};
+    use std::thread;
 
     #[derive(Clone)]
     struct DummyContext {}
@@ -451,4 +1091,159 @@ mod test {
             assert_eq!(id, task.id);
         }
     }
+
+    #[test]
+    fn test_fair_queue_prefers_least_running_group() {
+        let mut queue = FairQueue::new();
+        let f = DummyContextFactory {};
+        let mut push = |gid: u64, id: u64| {
+            let mut task = Task::new(gid, move |_: DummyContext| {}, f.create_context());
+            task.id = id;
+            queue.push(task);
+        };
+        push(1, 0);
+        push(1, 1);
+        push(2, 2);
+
+        // Both groups start at zero running tasks; the tie goes to the group
+        // whose front task is oldest (group 1, task 0).
+        let task = queue.pop().unwrap();
+        assert_eq!((task.gid, task.id), (1, 0));
+        queue.on_task_started(&task.gid);
+
+        // Group 1 now has a running task, so group 2 (still at zero) goes
+        // ahead of group 1's second task.
+        let task = queue.pop().unwrap();
+        assert_eq!((task.gid, task.id), (2, 2));
+        queue.on_task_started(&task.gid);
+
+        let task = queue.pop().unwrap();
+        assert_eq!((task.gid, task.id), (1, 1));
+    }
+
+    #[test]
+    fn test_fair_queue_caps_dont_deadlock() {
+        let mut queue = FairQueue::with_max_running_per_group(1);
+        let f = DummyContextFactory {};
+        let mut push = |gid: u64, id: u64| {
+            let mut task = Task::new(gid, move |_: DummyContext| {}, f.create_context());
+            task.id = id;
+            queue.push(task);
+        };
+        push(1, 0);
+        push(1, 1);
+        push(2, 2);
+
+        let task = queue.pop().unwrap();
+        assert_eq!((task.gid, task.id), (1, 0));
+        queue.on_task_started(&task.gid);
+
+        // Group 1 is now at its cap, so group 2 is served next even
+        // though group 1 still has a queued task.
+        let task = queue.pop().unwrap();
+        assert_eq!((task.gid, task.id), (2, 2));
+        queue.on_task_started(&task.gid);
+
+        // Every group with pending work is now at the cap; falling back
+        // instead of deadlocking still hands out group 1's last task.
+        let task = queue.pop().unwrap();
+        assert_eq!((task.gid, task.id), (1, 1));
+    }
+
+    #[test]
+    fn test_work_stealing_pool() {
+        let name = thd_name!("test_work_stealing_pool");
+        let concurrency = 4;
+        let f = DummyContextFactory {};
+        let mut pool = WorkStealingPool::new(name, concurrency, f);
+        let (tx, rx) = channel();
+        let task_num = 100;
+        for gid in 0..task_num {
+            let tx = tx.clone();
+            pool.execute(gid, move |_: DummyContext| {
+                tx.send(gid).unwrap();
+            });
+        }
+        let timeout = Duration::from_secs(2);
+        let mut received: Vec<_> = (0..task_num)
+            .map(|_| rx.recv_timeout(timeout).unwrap())
+            .collect();
+        received.sort();
+        assert_eq!(received, (0..task_num).collect::<Vec<_>>());
+        pool.stop().unwrap();
+        // Checked after `stop()` joins the workers: a task's count is only
+        // decremented after its body (which sends on `tx`) has returned.
+        assert_eq!(pool.get_task_count(), 0);
+    }
+
+    #[test]
+    fn test_work_stealing_pool_wakes_after_idle() {
+        let name = thd_name!("test_work_stealing_pool_wakes_after_idle");
+        let concurrency = 2;
+        let f = DummyContextFactory {};
+        let mut pool = WorkStealingPool::new(name, concurrency, f);
+        // Let every worker exhaust its spin rounds and fully park before
+        // submitting anything, to exercise the sleep/wake path rather than
+        // the busy-spin fast path.
+        thread::sleep(Duration::from_millis(50));
+        let (tx, rx) = channel();
+        pool.execute(0u64, move |_: DummyContext| {
+            tx.send(()).unwrap();
+        });
+        rx.recv_timeout(Duration::from_secs(2)).unwrap();
+        pool.stop().unwrap();
+    }
+
+    #[test]
+    fn test_throttling_runs_batched_tasks() {
+        let name = thd_name!("test_throttling_runs_batched_tasks");
+        let concurrency = 1;
+        let f = DummyContextFactory {};
+        let mut task_pool = ThreadPool::with_throttling(name,
+                                                          concurrency,
+                                                          FifoQueue::new(),
+                                                          f,
+                                                          Some(Duration::from_millis(5)));
+        let (tx, rx) = channel();
+        let task_num = 20;
+        for gid in 0..task_num {
+            let tx = tx.clone();
+            task_pool.execute(gid, move |_: DummyContext| {
+                tx.send(gid).unwrap();
+            });
+        }
+        let timeout = Duration::from_secs(2);
+        for gid in 0..task_num {
+            assert_eq!(rx.recv_timeout(timeout).unwrap(), gid);
+        }
+        task_pool.stop().unwrap();
+    }
+
+    #[test]
+    fn test_broadcast_runs_once_per_thread() {
+        let name = thd_name!("test_broadcast_runs_once_per_thread");
+        let concurrency = 4;
+        let f = DummyContextFactory {};
+        let mut task_pool = ThreadPool::new(name, concurrency, FifoQueue::<u64, _>::new(), f);
+        let counter = Arc::new(Mutex::new(0));
+        {
+            let counter = counter.clone();
+            task_pool.broadcast(move |_: DummyContext| {
+                *counter.lock().unwrap() += 1;
+            });
+        }
+        assert_eq!(*counter.lock().unwrap(), concurrency);
+        task_pool.stop().unwrap();
+    }
+
+    #[test]
+    fn test_execute_handle() {
+        let name = thd_name!("test_execute_handle");
+        let concurrency = 2;
+        let f = DummyContextFactory {};
+        let mut task_pool = ThreadPool::new(name, concurrency, FifoQueue::new(), f);
+        let handle = task_pool.execute_handle(0, move |_: DummyContext| 42);
+        assert_eq!(handle.wait().unwrap(), 42);
+        task_pool.stop().unwrap();
+    }
 }